@@ -13,96 +13,231 @@ use {
     std::{collections::HashMap, sync::Arc},
 };
 
-/// a set of accounts need to be stored.
-/// If there are too many to fit in 'Primary', the rest are put in 'Overflow'
-#[derive(Copy, Clone, Debug)]
-pub enum StorageSelector {
-    Primary,
-    Overflow,
-}
+/// identifies which destination chunk a subset of accounts should be stored in.
+/// Chunks are numbered in the order the corresponding `available_bytes` budgets were
+/// passed to `AccountsToStore::new`, plus one implicit final chunk that receives
+/// whatever doesn't fit in any of the explicit budgets.
+pub type StorageSelector = usize;
 
 /// reference a set of accounts to store
-/// The accounts may have to be split between 2 storages (primary and overflow) if there is not enough room in the primary storage.
-/// The 'store' functions need data stored in a slice of specific type.
-/// We need 1-2 of these slices constructed based on available bytes and individual account sizes.
-/// The slice arithmetic accross both hashes and account data gets messy. So, this struct abstracts that.
+/// The accounts may have to be split across more than one storage if there is not enough
+/// room in the earlier ones. The 'store' functions need data stored in a slice of specific
+/// type. We need one of these slices per destination storage, constructed based on available
+/// bytes and individual account sizes. The slice arithmetic accross both hashes and account
+/// data gets messy. So, this struct abstracts that.
 pub struct AccountsToStore<'a> {
     hashes: Vec<&'a Hash>,
     accounts: Vec<(&'a Pubkey, &'a StoredAccountMeta<'a>, Slot)>,
-    /// if 'accounts' contains more items than can be contained in the primary storage, then we have to split these accounts.
-    /// 'index_first_item_overflow' specifies the index of the first item in 'accounts' that will go into the overflow storage
-    index_first_item_overflow: usize,
+    /// end index (exclusive) of each chunk in 'accounts'/'hashes', in destination order.
+    /// has one entry per explicit budget in 'available_bytes', plus a final entry for the
+    /// implicit remainder chunk, which is always equal to 'accounts.len()'.
+    chunk_ends: Vec<usize>,
 }
 
 impl<'a> AccountsToStore<'a> {
-    /// break 'stored_accounts' into primary and overflow
-    /// available_bytes: how many bytes remain in the primary storage. Excess accounts will be directed to an overflow storage
+    /// break 'stored_accounts' into chunks destined for one storage per entry in
+    /// 'available_bytes', plus a final remainder chunk for whatever doesn't fit.
+    /// available_bytes: how many bytes remain in each destination storage, in order.
+    /// pack_by_size: if false (the default/deterministic behavior), accounts are visited in
+    /// hashmap order and placed in the first chunk they fit in, advancing to the next chunk
+    /// as soon as one doesn't fit (no look-ahead). If true, accounts are instead sorted by
+    /// size (descending) and packed first-fit-decreasing: every remaining account is tried
+    /// against a chunk's budget before moving to the next chunk, so smaller accounts can
+    /// still fill space a bigger one left behind. This trades away hashmap-order determinism
+    /// for a higher fill ratio.
     pub fn new(
-        mut available_bytes: u64,
+        available_bytes: &[u64],
+        stored_accounts: &'a HashMap<Pubkey, FoundStoredAccount>,
+        slot: Slot,
+        pack_by_size: bool,
+    ) -> Self {
+        if pack_by_size {
+            Self::new_packed(available_bytes, stored_accounts, slot)
+        } else {
+            Self::new_in_order(available_bytes, stored_accounts, slot)
+        }
+    }
+
+    /// default in-order split: see 'new' for details
+    fn new_in_order(
+        available_bytes: &[u64],
         stored_accounts: &'a HashMap<Pubkey, FoundStoredAccount>,
         slot: Slot,
     ) -> Self {
         let num_accounts = stored_accounts.len();
         let mut hashes = Vec::with_capacity(num_accounts);
         let mut accounts = Vec::with_capacity(num_accounts);
-        // index of the first account that doesn't fit in the current append vec
-        let mut index_first_item_overflow = num_accounts; // assume all fit
+        let mut budgets = available_bytes.iter().copied();
+        let mut current_budget = budgets.next().unwrap_or(0);
+        let mut chunk_ends = Vec::with_capacity(available_bytes.len() + 1);
         stored_accounts.iter().for_each(|account| {
             let account_size = account.1.account_size as u64;
-            if available_bytes >= account_size {
-                available_bytes = available_bytes.saturating_sub(account_size);
-            } else if index_first_item_overflow == num_accounts {
-                available_bytes = 0;
-                // the # of accounts we have so far seen is the most that will fit in the current ancient append vec
-                index_first_item_overflow = hashes.len();
+            // advance to the next chunk until this account fits, or we've run out of
+            // explicit budgets and the rest falls into the remainder chunk
+            while account_size > current_budget && chunk_ends.len() < available_bytes.len() {
+                chunk_ends.push(hashes.len());
+                current_budget = budgets.next().unwrap_or(0);
             }
+            current_budget = current_budget.saturating_sub(account_size);
             hashes.push(account.1.account.hash);
             // we have to specify 'slot' here because we are writing to an ancient append vec and squashing slots,
             // so we need to update the previous accounts index entry for this account from 'slot' to 'ancient_slot'
             accounts.push((&account.1.account.meta.pubkey, &account.1.account, slot));
         });
+        // any budgets we never got to (ran out of accounts first) end at the same point as the remainder
+        while chunk_ends.len() < available_bytes.len() {
+            chunk_ends.push(hashes.len());
+        }
+        chunk_ends.push(hashes.len());
+        Self {
+            hashes,
+            accounts,
+            chunk_ends,
+        }
+    }
+
+    /// first-fit-decreasing split: see 'new' for details
+    fn new_packed(
+        available_bytes: &[u64],
+        stored_accounts: &'a HashMap<Pubkey, FoundStoredAccount>,
+        slot: Slot,
+    ) -> Self {
+        let mut candidates: Vec<_> = stored_accounts
+            .iter()
+            .map(|account| {
+                let account_size = account.1.account_size as u64;
+                let hash = account.1.account.hash;
+                let entry = (&account.1.account.meta.pubkey, &account.1.account, slot);
+                (account_size, hash, entry)
+            })
+            .collect();
+        // largest accounts first, so each chunk is offered the best candidates to fill it
+        candidates.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+
+        let num_accounts = candidates.len();
+        let mut hashes = Vec::with_capacity(num_accounts);
+        let mut accounts = Vec::with_capacity(num_accounts);
+        let mut chunk_ends = Vec::with_capacity(available_bytes.len() + 1);
+        let mut remaining = candidates;
+        for &budget in available_bytes {
+            let mut available = budget;
+            let mut leftover = Vec::with_capacity(remaining.len());
+            for candidate in remaining {
+                if candidate.0 <= available {
+                    available -= candidate.0;
+                    hashes.push(candidate.1);
+                    accounts.push(candidate.2);
+                } else {
+                    // doesn't fit in this chunk - keep trying it against later chunks
+                    leftover.push(candidate);
+                }
+            }
+            remaining = leftover;
+            chunk_ends.push(hashes.len());
+        }
+        // whatever is left over doesn't fit any explicit budget - goes in the remainder chunk
+        for candidate in remaining {
+            hashes.push(candidate.1);
+            accounts.push(candidate.2);
+        }
+        chunk_ends.push(hashes.len());
+
         Self {
             hashes,
             accounts,
-            index_first_item_overflow,
+            chunk_ends,
         }
     }
 
-    /// get the accounts and hashes to store in the given 'storage'
+    /// get the accounts and hashes to store in the given destination chunk
+    /// panics if 'chunk' is not < 'self.num_chunks()'
     pub fn get(
         &self,
-        storage: StorageSelector,
+        chunk: StorageSelector,
     ) -> (
         &[(&'a Pubkey, &'a StoredAccountMeta<'a>, Slot)],
         &[&'a Hash],
     ) {
-        let range = match storage {
-            StorageSelector::Primary => 0..self.index_first_item_overflow,
-            StorageSelector::Overflow => self.index_first_item_overflow..self.accounts.len(),
+        assert!(
+            chunk < self.num_chunks(),
+            "chunk {} out of range: only {} chunks exist",
+            chunk,
+            self.num_chunks()
+        );
+        let start = if chunk == 0 {
+            0
+        } else {
+            self.chunk_ends[chunk - 1]
         };
-        (&self.accounts[range.clone()], &self.hashes[range])
+        let end = self.chunk_ends[chunk];
+        (&self.accounts[start..end], &self.hashes[start..end])
+    }
+
+    /// how many destination chunks accounts were split across, including the remainder chunk
+    pub fn num_chunks(&self) -> usize {
+        self.chunk_ends.len()
+    }
+}
+
+/// largest multiple of the normal max append vec file size we'll let an ancient append vec
+/// grow to. Far beyond any real operator use case, and small enough that multiplying it by
+/// 'MAXIMUM_APPEND_VEC_FILE_SIZE' has no real risk of approaching u64::MAX.
+const MAX_ANCIENT_APPEND_VEC_SIZE_MULTIPLE: u64 = 1024;
+
+/// configures how large an ancient append vec is allowed to grow.
+/// 'size_multiple' is expressed as a multiple of the normal max append vec file size, so that
+/// operators can choose to coalesce more old slots into a single larger ancient file and cut
+/// down on the total number of storage files (and the resulting open-file/mmap pressure) on
+/// long-running validators.
+#[derive(Debug, Clone, Copy)]
+pub struct AncientAppendVecConfig {
+    size_multiple: u64,
+}
+
+impl AncientAppendVecConfig {
+    /// 'size_multiple' is clamped to '[1, MAX_ANCIENT_APPEND_VEC_SIZE_MULTIPLE]'.
+    /// 1 means an ancient append vec is the same size as a normal append vec.
+    pub fn new(size_multiple: u64) -> Self {
+        Self {
+            size_multiple: size_multiple.clamp(1, MAX_ANCIENT_APPEND_VEC_SIZE_MULTIPLE),
+        }
+    }
+
+    pub fn size_multiple(&self) -> u64 {
+        self.size_multiple
+    }
+}
+
+impl Default for AncientAppendVecConfig {
+    fn default() -> Self {
+        Self::new(1)
     }
 }
 
 /// capacity of an ancient append vec
-pub fn get_ancient_append_vec_capacity() -> u64 {
+pub fn get_ancient_append_vec_capacity(config: &AncientAppendVecConfig) -> u64 {
     use crate::append_vec::MAXIMUM_APPEND_VEC_FILE_SIZE;
     // smaller than max by a bit just in case
     // some functions add slop on allocation
-    MAXIMUM_APPEND_VEC_FILE_SIZE - 2048
+    MAXIMUM_APPEND_VEC_FILE_SIZE
+        .saturating_mul(config.size_multiple())
+        .saturating_sub(2048)
 }
 
 /// true iff storage is ancient size and is almost completely full
-pub fn is_full_ancient(storage: &AppendVec) -> bool {
+pub fn is_full_ancient(storage: &AppendVec, config: &AncientAppendVecConfig) -> bool {
     // not sure of slop amount here. Maybe max account size with 10MB data?
     // append vecs can't usually be made entirely full
-    let threshold_bytes = 10_000;
-    is_ancient(storage) && storage.remaining_bytes() < threshold_bytes
+    // the slop scales with the configured ancient size so a larger ancient file is
+    // considered "full" once its slack is proportionally as small as a full normal one's
+    let threshold_bytes = 10_000u64.saturating_mul(config.size_multiple());
+    is_ancient(storage, config) && storage.remaining_bytes() < threshold_bytes
 }
 
-/// is this a max-size append vec designed to be used as an ancient append vec?
-pub fn is_ancient(storage: &AppendVec) -> bool {
-    storage.capacity() >= get_ancient_append_vec_capacity()
+/// is this an append vec at least as large as the configured ancient capacity, designed to be
+/// used as an ancient append vec?
+pub fn is_ancient(storage: &AppendVec, config: &AncientAppendVecConfig) -> bool {
+    storage.capacity() >= get_ancient_append_vec_capacity(config)
 }
 
 /// return true if the accounts in this slot should be moved to an ancient append vec
@@ -112,11 +247,12 @@ pub fn should_move_to_ancient_append_vec(
     all_storages: &SnapshotStorage,
     current_ancient: &mut Option<(Slot, Arc<AccountStorageEntry>)>,
     slot: Slot,
+    config: &AncientAppendVecConfig,
 ) -> bool {
     if current_ancient.is_none() && all_storages.len() == 1 {
         let first_storage = all_storages.first().unwrap();
-        if is_ancient(&first_storage.accounts) {
-            if is_full_ancient(&first_storage.accounts) {
+        if is_ancient(&first_storage.accounts, config) {
+            if is_full_ancient(&first_storage.accounts, config) {
                 return false; // skip this full ancient append vec completely
             }
             // this slot is ancient and can become the 'current' ancient for other slots to be squashed into
@@ -142,8 +278,8 @@ pub mod tests {
     fn test_accounts_to_store_simple() {
         let map = vec![].into_iter().collect();
         let slot = 1;
-        let accounts_to_store = AccountsToStore::new(0, &map, slot);
-        for selector in [StorageSelector::Primary, StorageSelector::Overflow] {
+        let accounts_to_store = AccountsToStore::new(&[0], &map, slot, false);
+        for selector in 0..accounts_to_store.num_chunks() {
             let (accounts, hash) = accounts_to_store.get(selector);
             assert!(accounts.is_empty());
             assert!(hash.is_empty());
@@ -190,12 +326,12 @@ pub mod tests {
             account_size,
         };
         let map = vec![(pubkey, found)].into_iter().collect();
-        for (selector, available_bytes) in [
-            (StorageSelector::Primary, account_size),
-            (StorageSelector::Overflow, account_size - 1),
-        ] {
+        const PRIMARY: StorageSelector = 0;
+        const OVERFLOW: StorageSelector = 1;
+        for (selector, available_bytes) in [(PRIMARY, account_size), (OVERFLOW, account_size - 1)] {
             let slot = 1;
-            let accounts_to_store = AccountsToStore::new(available_bytes as u64, &map, slot);
+            let accounts_to_store =
+                AccountsToStore::new(&[available_bytes as u64], &map, slot, false);
             let (accounts, hashes) = accounts_to_store.get(selector);
             assert_eq!(
                 accounts,
@@ -205,50 +341,193 @@ pub mod tests {
                 "mismatch"
             );
             assert_eq!(hashes, vec![&hash]);
-            let (accounts, hash) = accounts_to_store.get(get_opposite(&selector));
+            let (accounts, hash) = accounts_to_store.get(1 - selector);
             assert!(accounts.is_empty());
             assert!(hash.is_empty());
         }
     }
-    fn get_opposite(selector: &StorageSelector) -> StorageSelector {
-        match selector {
-            StorageSelector::Overflow => StorageSelector::Primary,
-            StorageSelector::Primary => StorageSelector::Overflow,
+
+    #[test]
+    fn test_accounts_to_store_many_chunks() {
+        // budgets for 2 explicit storages, plus the implicit remainder chunk
+        let budgets = [5u64, 5u64];
+        let accounts: Vec<_> = (0..6)
+            .map(|i| {
+                let pubkey = Pubkey::new(&[i as u8 + 1; 32]);
+                let account = AccountSharedData::default();
+                let account_meta = AccountMeta {
+                    lamports: 1,
+                    owner: Pubkey::new(&[2; 32]),
+                    executable: false,
+                    rent_epoch: 0,
+                };
+                let hash = Hash::new(&[i as u8 + 1; 32]);
+                (pubkey, account, account_meta, hash)
+            })
+            .collect();
+        // build a HashMap where every entry has the same account_size: 5, 5, 5, 5, 5, 5
+        let mut map = HashMap::new();
+        let metas: Vec<_> = accounts
+            .iter()
+            .map(|(pubkey, _, _, _)| StoredMeta {
+                write_version: 0,
+                pubkey: *pubkey,
+                data_len: 0,
+            })
+            .collect();
+        for (i, (pubkey, account, account_meta, hash)) in accounts.iter().enumerate() {
+            let stored_account = StoredAccountMeta {
+                meta: &metas[i],
+                account_meta,
+                data: account.data(),
+                offset: i,
+                stored_size: 5,
+                hash,
+            };
+            map.insert(
+                *pubkey,
+                FoundStoredAccount {
+                    account: stored_account,
+                    store_id: AppendVecId::default(),
+                    account_size: 5,
+                },
+            );
+        }
+        let slot = 1;
+        let accounts_to_store = AccountsToStore::new(&budgets, &map, slot, false);
+        assert_eq!(accounts_to_store.num_chunks(), 3);
+        let total: usize = (0..accounts_to_store.num_chunks())
+            .map(|chunk| accounts_to_store.get(chunk).0.len())
+            .sum();
+        assert_eq!(total, 6, "every account must land in exactly one chunk");
+        // each of the first two chunks has exactly enough room for 1 account (budget 5 / size 5)
+        assert_eq!(accounts_to_store.get(0).0.len(), 1);
+        assert_eq!(accounts_to_store.get(1).0.len(), 1);
+        // the rest overflow to the remainder chunk
+        assert_eq!(accounts_to_store.get(2).0.len(), 4);
+    }
+
+    #[test]
+    fn test_accounts_to_store_packed_by_size() {
+        // sizes chosen so in-order placement (in hashmap order) would likely leave slack in
+        // the single budget, but first-fit-decreasing fills it exactly: 8 + 2 == 10
+        let sizes = [8u64, 5, 5, 2];
+        let accounts: Vec<_> = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &size)| {
+                let pubkey = Pubkey::new(&[i as u8 + 1; 32]);
+                let account_meta = AccountMeta {
+                    lamports: 1,
+                    owner: Pubkey::new(&[2; 32]),
+                    executable: false,
+                    rent_epoch: 0,
+                };
+                let hash = Hash::new(&[i as u8 + 1; 32]);
+                (pubkey, account_meta, hash, size)
+            })
+            .collect();
+        let metas: Vec<_> = accounts
+            .iter()
+            .map(|(pubkey, _, _, _)| StoredMeta {
+                write_version: 0,
+                pubkey: *pubkey,
+                data_len: 0,
+            })
+            .collect();
+        let account_data = AccountSharedData::default();
+        let mut map = HashMap::new();
+        for (i, (pubkey, account_meta, hash, size)) in accounts.iter().enumerate() {
+            let stored_account = StoredAccountMeta {
+                meta: &metas[i],
+                account_meta,
+                data: account_data.data(),
+                offset: i,
+                stored_size: *size as usize,
+                hash,
+            };
+            map.insert(
+                *pubkey,
+                FoundStoredAccount {
+                    account: stored_account,
+                    store_id: AppendVecId::default(),
+                    account_size: *size as usize,
+                },
+            );
         }
+        let slot = 1;
+        let accounts_to_store = AccountsToStore::new(&[10], &map, slot, true);
+        assert_eq!(accounts_to_store.num_chunks(), 2);
+        // the 8-byte and 2-byte accounts pack perfectly into the 10-byte budget
+        assert_eq!(accounts_to_store.get(0).0.len(), 2);
+        // the two 5-byte accounts didn't fit alongside the 8-byte one and spill to the remainder
+        assert_eq!(accounts_to_store.get(1).0.len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "chunk 2 out of range: only 2 chunks exist")]
+    fn test_accounts_to_store_get_out_of_range() {
+        let map = vec![].into_iter().collect();
+        let slot = 1;
+        // a single budget plus the implicit remainder chunk means valid indices are 0 and 1
+        let accounts_to_store = AccountsToStore::new(&[0], &map, slot, false);
+        assert_eq!(accounts_to_store.num_chunks(), 2);
+        accounts_to_store.get(2);
     }
 
     #[test]
     fn test_get_ancient_append_vec_capacity() {
+        let config = AncientAppendVecConfig::default();
         assert_eq!(
-            get_ancient_append_vec_capacity(),
+            get_ancient_append_vec_capacity(&config),
             crate::append_vec::MAXIMUM_APPEND_VEC_FILE_SIZE - 2048
         );
     }
 
+    #[test]
+    fn test_get_ancient_append_vec_capacity_multiple() {
+        let config = AncientAppendVecConfig::new(3);
+        assert_eq!(
+            get_ancient_append_vec_capacity(&config),
+            crate::append_vec::MAXIMUM_APPEND_VEC_FILE_SIZE * 3 - 2048
+        );
+    }
+
+    #[test]
+    fn test_ancient_append_vec_config_clamps_size_multiple() {
+        assert_eq!(AncientAppendVecConfig::new(0).size_multiple(), 1);
+        assert_eq!(
+            AncientAppendVecConfig::new(u64::MAX).size_multiple(),
+            MAX_ANCIENT_APPEND_VEC_SIZE_MULTIPLE
+        );
+    }
+
     #[test]
     fn test_is_ancient() {
+        let config = AncientAppendVecConfig::default();
         for (size, expected_ancient) in [
-            (get_ancient_append_vec_capacity() + 1, true),
-            (get_ancient_append_vec_capacity(), true),
-            (get_ancient_append_vec_capacity() - 1, false),
+            (get_ancient_append_vec_capacity(&config) + 1, true),
+            (get_ancient_append_vec_capacity(&config), true),
+            (get_ancient_append_vec_capacity(&config) - 1, false),
         ] {
             let tf = crate::append_vec::test_utils::get_append_vec_path("test_is_ancient");
             let (_temp_dirs, _paths) = get_temp_accounts_paths(1).unwrap();
             let av = AppendVec::new(&tf.path, true, size as usize);
 
-            assert_eq!(expected_ancient, is_ancient(&av));
-            assert!(!is_full_ancient(&av));
+            assert_eq!(expected_ancient, is_ancient(&av, &config));
+            assert!(!is_full_ancient(&av, &config));
         }
     }
 
     #[test]
     fn test_is_full_ancient() {
-        let size = get_ancient_append_vec_capacity();
+        let config = AncientAppendVecConfig::default();
+        let size = get_ancient_append_vec_capacity(&config);
         let tf = crate::append_vec::test_utils::get_append_vec_path("test_is_ancient");
         let (_temp_dirs, _paths) = get_temp_accounts_paths(1).unwrap();
         let av = AppendVec::new(&tf.path, true, size as usize);
-        assert!(is_ancient(&av));
-        assert!(!is_full_ancient(&av));
+        assert!(is_ancient(&av, &config));
+        assert!(!is_full_ancient(&av, &config));
         let overhead = 400;
         let data_len = size - overhead;
         let mut account = AccountSharedData::default();
@@ -260,7 +539,11 @@ pub mod tests {
             data_len: data_len as u64,
         };
         av.append_accounts(&[(sm, Some(&account))], &[Hash::default()]);
-        assert!(is_ancient(&av));
-        assert!(is_full_ancient(&av), "Remaining: {}", av.remaining_bytes());
+        assert!(is_ancient(&av, &config));
+        assert!(
+            is_full_ancient(&av, &config),
+            "Remaining: {}",
+            av.remaining_bytes()
+        );
     }
 }